@@ -1,9 +1,16 @@
 extern crate byteorder;
+extern crate crc32fast;
+extern crate md5;
 
 use std::str;
-use std::io::{self, BufRead, Error, ErrorKind};
+use std::cmp;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::Values;
+use std::io::{self, BufRead, Read, Write, Seek, SeekFrom, Error, ErrorKind};
 
-use byteorder::ReadBytesExt;
+use byteorder::{ReadBytesExt, WriteBytesExt};
 
 type VPKEndian = byteorder::LittleEndian;
 
@@ -97,6 +104,133 @@ impl<R: BufRead> DirReader<R> {
     pub fn data_len(&self) -> Option<usize> {
         self.header.header_v2.map(|h| h.file_data_section_size as usize)
     }
+
+    /// Byte length of the fixed header, before the directory tree.
+    #[inline]
+    fn header_len(&self) -> u64 {
+        12 + 16 * self.header.header_v2.is_some() as u64
+    }
+
+    /// Parse the v2 MD5 and signature sections that follow the tree and file
+    /// data. Returns empty defaults for v1 paks, which have no such sections.
+    pub fn checksums<S: Read + Seek>(&self, stream: &mut S) -> io::Result<DirChecksums> {
+        let h2 = match self.header.header_v2 {
+            Some(h) => h,
+            None => return Ok(DirChecksums::default())
+        };
+
+        let archive_md5_offset = self.data_offset() as u64 + h2.file_data_section_size as u64;
+        let other_md5_offset = archive_md5_offset + h2.archive_md5_section_size as u64;
+        let signature_offset = other_md5_offset + h2.other_md5_section_size as u64;
+
+        stream.seek(SeekFrom::Start(archive_md5_offset))?;
+        let mut archive_md5 = Vec::with_capacity(h2.archive_md5_section_size as usize / 28);
+        for _ in 0..h2.archive_md5_section_size as usize / 28 {
+            let archive_index = stream.read_u32::<VPKEndian>()?;
+            let starting_offset = stream.read_u32::<VPKEndian>()?;
+            let count = stream.read_u32::<VPKEndian>()?;
+            let mut md5 = [0u8; 16];
+            stream.read_exact(&mut md5)?;
+            archive_md5.push(ArchiveMd5Entry { archive_index, starting_offset, count, md5 });
+        }
+
+        let other_md5 = if h2.other_md5_section_size >= 48 {
+            stream.seek(SeekFrom::Start(other_md5_offset))?;
+            let mut tree_md5 = [0u8; 16];
+            let mut archive_md5_section_md5 = [0u8; 16];
+            let mut whole_file_md5 = [0u8; 16];
+            stream.read_exact(&mut tree_md5)?;
+            stream.read_exact(&mut archive_md5_section_md5)?;
+            stream.read_exact(&mut whole_file_md5)?;
+            Some(OtherMd5 { tree_md5, archive_md5_section_md5, whole_file_md5 })
+        } else {
+            None
+        };
+
+        let signature = if h2.signature_section_size > 0 {
+            stream.seek(SeekFrom::Start(signature_offset))?;
+            let public_key_len = stream.read_u32::<VPKEndian>()?;
+            let mut public_key = vec![0; public_key_len as usize];
+            stream.read_exact(&mut public_key)?;
+            let signature_len = stream.read_u32::<VPKEndian>()?;
+            let mut signature = vec![0; signature_len as usize];
+            stream.read_exact(&mut signature)?;
+            Some(Signature { public_key, signature })
+        } else {
+            None
+        };
+
+        Ok(DirChecksums { archive_md5, other_md5, signature })
+    }
+
+    /// Recompute the MD5 over the directory-tree bytes and over the archive-MD5
+    /// section and compare them against the digests stored in the other-MD5
+    /// section, reporting which checks passed.
+    pub fn verify<S: Read + Seek>(&self, stream: &mut S) -> io::Result<VerifyResult> {
+        let h2 = match self.header.header_v2 {
+            Some(h) => h,
+            None => return Ok(VerifyResult::default())
+        };
+        let other = match self.checksums(stream)?.other_md5 {
+            Some(o) => o,
+            None => return Ok(VerifyResult::default())
+        };
+
+        let mut tree = vec![0; self.header.tree_size as usize];
+        stream.seek(SeekFrom::Start(self.header_len()))?;
+        stream.read_exact(&mut tree)?;
+
+        let archive_md5_offset = self.data_offset() as u64 + h2.file_data_section_size as u64;
+        let mut archive_md5_section = vec![0; h2.archive_md5_section_size as usize];
+        stream.seek(SeekFrom::Start(archive_md5_offset))?;
+        stream.read_exact(&mut archive_md5_section)?;
+
+        Ok(VerifyResult {
+            tree_md5_ok: md5::compute(&tree).0 == other.tree_md5,
+            archive_md5_section_md5_ok: md5::compute(&archive_md5_section).0 == other.archive_md5_section_md5
+        })
+    }
+}
+
+/// One record of the archive-MD5 section: the MD5 of a `count`-byte run
+/// starting at `starting_offset` within archive `archive_index`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveMd5Entry {
+    pub archive_index: u32,
+    pub starting_offset: u32,
+    pub count: u32,
+    pub md5: [u8; 16]
+}
+
+/// The other-MD5 section: digests of the tree, of the archive-MD5 section, and
+/// of the whole directory file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OtherMd5 {
+    pub tree_md5: [u8; 16],
+    pub archive_md5_section_md5: [u8; 16],
+    pub whole_file_md5: [u8; 16]
+}
+
+/// The signature section: the public key and signature over the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>
+}
+
+/// The parsed v2 integrity sections.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DirChecksums {
+    pub archive_md5: Vec<ArchiveMd5Entry>,
+    pub other_md5: Option<OtherMd5>,
+    pub signature: Option<Signature>
+}
+
+/// Outcome of [`DirReader::verify`]: which recomputed digests matched.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VerifyResult {
+    pub tree_md5_ok: bool,
+    pub archive_md5_section_md5_ok: bool
 }
 
 impl<R: BufRead> Iterator for DirReader<R> {
@@ -199,3 +333,400 @@ impl<R: BufRead> Iterator for DirReader<R> {
         }
     }
 }
+
+/// A single entry queued in a [`DirWriter`], mirroring the fields of
+/// [`DirEntry`] that get serialized into the per-file block.
+#[derive(Debug, Default, Clone)]
+pub struct WriteEntry {
+    pub crc: u32,
+    pub preload_data: Vec<u8>,
+    pub archive_index: Option<u16>,
+    pub entry_offset: u32,
+    pub entry_length: u32
+}
+
+/// `Iterator` to `Write` counterpart of [`DirReader`] that serializes a VPK
+/// directory tree.
+///
+/// Entries are grouped by extension, then directory, then filename, exactly as
+/// [`DirReader::next`] expects to parse them back. Pass the `' '` sentinel for
+/// an empty extension, root directory, or nameless file, just as it appears on
+/// disk.
+pub struct DirWriter<W: Write + Seek> {
+    writer: W,
+    header_v2: Option<DirHeader2>,
+    tree: BTreeMap<String, BTreeMap<String, Vec<(String, WriteEntry)>>>
+}
+
+impl<W: Write + Seek> DirWriter<W> {
+    /// Create a writer that emits a version 1 pak.
+    pub fn new(writer: W) -> DirWriter<W> {
+        DirWriter { writer, header_v2: None, tree: BTreeMap::new() }
+    }
+
+    /// Create a writer that emits a version 2 pak, recording the section sizes
+    /// that follow the directory tree in the header.
+    pub fn with_header2(
+        writer: W,
+        file_data_section_size: u32,
+        archive_md5_section_size: u32,
+        other_md5_section_size: u32,
+        signature_section_size: u32
+    ) -> DirWriter<W> {
+        DirWriter {
+            writer,
+            header_v2: Some(DirHeader2 {
+                file_data_section_size,
+                archive_md5_section_size,
+                other_md5_section_size,
+                signature_section_size
+            }),
+            tree: BTreeMap::new()
+        }
+    }
+
+    /// Queue an entry under the given extension, directory, and filename.
+    pub fn add(&mut self, extension: &str, directory: &str, filename: &str, entry: WriteEntry) {
+        self.tree
+            .entry(extension.to_owned())
+            .or_insert_with(BTreeMap::new)
+            .entry(directory.to_owned())
+            .or_insert_with(Vec::new)
+            .push((filename.to_owned(), entry));
+    }
+
+    /// Serialize the header and directory tree, returning the underlying writer
+    /// positioned at the end of the tree (where the file data section begins).
+    pub fn finish(self) -> io::Result<W> {
+        let DirWriter { mut writer, header_v2, tree } = self;
+
+        writer.seek(SeekFrom::Start(0))?;
+        writer.write_u32::<VPKEndian>(0x55aa1234)?;
+        writer.write_u32::<VPKEndian>(if header_v2.is_some() { 2 } else { 1 })?;
+        // Patched with the real tree size once the tree has been written.
+        writer.write_u32::<VPKEndian>(0)?;
+        if let Some(h) = header_v2 {
+            writer.write_u32::<VPKEndian>(h.file_data_section_size)?;
+            writer.write_u32::<VPKEndian>(h.archive_md5_section_size)?;
+            writer.write_u32::<VPKEndian>(h.other_md5_section_size)?;
+            writer.write_u32::<VPKEndian>(h.signature_section_size)?;
+        }
+
+        let tree_start = writer.seek(SeekFrom::Current(0))?;
+        for (extension, dirs) in &tree {
+            write_cstr(&mut writer, extension)?;
+            for (directory, files) in dirs {
+                write_cstr(&mut writer, directory)?;
+                for &(ref filename, ref entry) in files {
+                    write_cstr(&mut writer, filename)?;
+                    writer.write_u32::<VPKEndian>(entry.crc)?;
+                    writer.write_u16::<VPKEndian>(entry.preload_data.len() as u16)?;
+                    writer.write_u16::<VPKEndian>(entry.archive_index.unwrap_or(0x7fff))?;
+                    writer.write_u32::<VPKEndian>(entry.entry_offset)?;
+                    writer.write_u32::<VPKEndian>(entry.entry_length)?;
+                    writer.write_u16::<VPKEndian>(0xffff)?;
+                    writer.write_all(&entry.preload_data)?;
+                }
+                // Empty filename terminates the directory's files.
+                writer.write_u8(0)?;
+            }
+            // Empty directory terminates the extension's directories.
+            writer.write_u8(0)?;
+        }
+        // Empty extension terminates the tree.
+        writer.write_u8(0)?;
+
+        let tree_end = writer.seek(SeekFrom::Current(0))?;
+        writer.seek(SeekFrom::Start(8))?;
+        writer.write_u32::<VPKEndian>((tree_end - tree_start) as u32)?;
+        writer.seek(SeekFrom::Start(tree_end))?;
+
+        Ok(writer)
+    }
+}
+
+/// Write a null-terminated string, the encoding used for every tree component.
+fn write_cstr<W: Write>(writer: &mut W, s: &str) -> io::Result<()> {
+    writer.write_all(s.as_bytes())?;
+    writer.write_u8(0)
+}
+
+/// Opens the backing files of a pak: the directory file itself and the sibling
+/// numbered archives (`pak01_000.vpk`, `pak01_001.vpk`, …).
+pub trait ArchiveSource {
+    type Stream: Read + Seek;
+
+    /// Open the directory file (where preload data and non-split entries live).
+    fn open_dir(&self) -> io::Result<Self::Stream>;
+    /// Open the numbered archive holding entries with the given archive index.
+    fn open_archive(&self, index: u16) -> io::Result<Self::Stream>;
+}
+
+/// [`ArchiveSource`] backed by files on disk, named off the directory pak.
+pub struct FileArchiveSource {
+    dir_path: PathBuf,
+    prefix: PathBuf
+}
+
+impl FileArchiveSource {
+    /// Derive the archive naming from the path to a directory pak, e.g.
+    /// `pak01_dir.vpk` yields numbered archives `pak01_NNN.vpk`.
+    pub fn new<P: AsRef<Path>>(dir_path: P) -> FileArchiveSource {
+        let dir_path = dir_path.as_ref().to_owned();
+        let name = dir_path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let stem = name
+            .strip_suffix("_dir.vpk")
+            .or_else(|| name.strip_suffix(".vpk"))
+            .unwrap_or(name);
+        let prefix = dir_path.with_file_name(stem);
+        FileArchiveSource { dir_path, prefix }
+    }
+}
+
+impl ArchiveSource for FileArchiveSource {
+    type Stream = File;
+
+    fn open_dir(&self) -> io::Result<File> {
+        File::open(&self.dir_path)
+    }
+
+    fn open_archive(&self, index: u16) -> io::Result<File> {
+        let mut path = self.prefix.clone().into_os_string();
+        path.push(format!("_{:03}.vpk", index));
+        File::open(path)
+    }
+}
+
+/// A logical read surface over one [`DirEntry`]'s bytes, transparently
+/// prepending the inline preload data before the archived region.
+pub struct EntryReader<S: Read + Seek> {
+    preload: Vec<u8>,
+    stream: S,
+    data_start: u64,
+    data_len: u64,
+    pos: u64
+}
+
+impl<S: Read + Seek> EntryReader<S> {
+    fn new(preload: Vec<u8>, stream: S, data_start: u64, data_len: u64) -> EntryReader<S> {
+        EntryReader { preload, stream, data_start, data_len, pos: 0 }
+    }
+
+    /// Total length of the entry: preload bytes plus the archived region.
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.preload.len() as u64 + self.data_len
+    }
+
+    /// Whether the entry carries no bytes at all.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<S: Read + Seek> Read for EntryReader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let preload_len = self.preload.len() as u64;
+        let total = self.len();
+        let mut written = 0;
+
+        // Serve from the inline preload data first.
+        if self.pos < preload_len {
+            let off = self.pos as usize;
+            let n = cmp::min(buf.len(), self.preload.len() - off);
+            buf[..n].copy_from_slice(&self.preload[off..off + n]);
+            written += n;
+            self.pos += n as u64;
+        }
+
+        // Then from the archived region.
+        if written < buf.len() && self.pos < total {
+            let rel = self.pos - preload_len;
+            let remaining = self.data_len - rel;
+            let want = cmp::min((buf.len() - written) as u64, remaining) as usize;
+            self.stream.seek(SeekFrom::Start(self.data_start + rel))?;
+            let n = self.stream.read(&mut buf[written..written + want])?;
+            written += n;
+            self.pos += n as u64;
+        }
+
+        Ok(written)
+    }
+}
+
+impl<S: Read + Seek> Seek for EntryReader<S> {
+    fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
+        let total = self.len();
+        let pos = match from {
+            SeekFrom::Start(n) => n as i64,
+            SeekFrom::End(n) => total as i64 + n,
+            SeekFrom::Current(n) => self.pos as i64 + n
+        };
+        if pos < 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "seek before start of entry"));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+/// Reads file contents out of a pak, transparently spanning the directory file
+/// and its sibling numbered archives.
+pub struct VpkArchive<S: ArchiveSource> {
+    source: S,
+    data_offset: u64
+}
+
+impl VpkArchive<FileArchiveSource> {
+    /// Open a pak on disk given the path to its directory file and the data
+    /// offset reported by [`DirReader::data_offset`].
+    pub fn at_path<P: AsRef<Path>>(dir_path: P, data_offset: usize) -> VpkArchive<FileArchiveSource> {
+        VpkArchive::new(FileArchiveSource::new(dir_path), data_offset)
+    }
+}
+
+impl<S: ArchiveSource> VpkArchive<S> {
+    /// Build an accessor from an [`ArchiveSource`] and the data offset reported
+    /// by [`DirReader::data_offset`].
+    pub fn new(source: S, data_offset: usize) -> VpkArchive<S> {
+        VpkArchive { source, data_offset: data_offset as u64 }
+    }
+
+    /// Open a `Read`/`Seek` stream over an entry's bytes. Entries with no
+    /// archive index are served from the directory file at `data_offset +
+    /// entry_offset`; otherwise from the numbered archive at `entry_offset`.
+    pub fn open_entry(&self, entry: &DirEntry) -> io::Result<EntryReader<S::Stream>> {
+        let (stream, data_start) = match entry.archive_index {
+            None => (self.source.open_dir()?, self.data_offset + entry.entry_offset as u64),
+            Some(index) => (self.source.open_archive(index)?, entry.entry_offset as u64)
+        };
+        Ok(EntryReader::new(entry.preload_data.clone(), stream, data_start, entry.entry_length as u64))
+    }
+
+    /// Stream an entry's bytes (preload plus archive region) through CRC32.
+    fn entry_crc(&self, entry: &DirEntry) -> io::Result<u32> {
+        let mut reader = self.open_entry(entry)?;
+        let mut hasher = crc32fast::Hasher::new();
+        let mut buf = [0u8; 8192];
+        loop {
+            let read = reader.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+        }
+        Ok(hasher.finalize())
+    }
+
+    /// Verify a single entry's contents against its stored [`DirEntry::crc`],
+    /// erroring if they disagree.
+    pub fn verify_crc(&self, entry: &DirEntry) -> io::Result<()> {
+        let found = self.entry_crc(entry)?;
+        if found == entry.crc {
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::InvalidData, format!(
+                "crc mismatch for {}: expected {:#010x}, found {:#010x}",
+                entry.file, entry.crc, found
+            )))
+        }
+    }
+
+    /// Verify every entry's CRC32, collecting a summary of the mismatches
+    /// rather than stopping at the first failure.
+    pub fn verify_all<'a, I>(&self, entries: I) -> io::Result<VerifySummary>
+    where
+        I: IntoIterator<Item = &'a DirEntry>
+    {
+        let mut summary = VerifySummary::default();
+        for entry in entries {
+            summary.checked += 1;
+            let found = self.entry_crc(entry)?;
+            if found != entry.crc {
+                summary.failures.push(CrcMismatch {
+                    file: entry.file.clone(),
+                    expected: entry.crc,
+                    found
+                });
+            }
+        }
+        Ok(summary)
+    }
+}
+
+/// A single CRC32 mismatch found during [`VpkArchive::verify_all`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrcMismatch {
+    pub file: String,
+    pub expected: u32,
+    pub found: u32
+}
+
+/// Summary of a bulk CRC32 verification pass.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifySummary {
+    pub checked: usize,
+    pub failures: Vec<CrcMismatch>
+}
+
+impl VerifySummary {
+    /// Whether every checked entry matched its stored CRC.
+    #[inline]
+    pub fn all_passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// A random-access view of a directory tree, built by consuming a
+/// [`DirReader`] once. Entries are keyed on the same reconstructed path that
+/// [`DirReader::next`] yields in [`DirEntry::file`], so lookups match the paths
+/// seen during iteration.
+pub struct DirIndex {
+    entries: HashMap<String, DirEntry>
+}
+
+impl DirIndex {
+    /// Consume a [`DirReader`], indexing every entry by its path.
+    pub fn from_reader<R: BufRead>(reader: DirReader<R>) -> io::Result<DirIndex> {
+        let mut entries = HashMap::new();
+        for entry in reader {
+            let entry = entry?;
+            entries.insert(entry.file.clone(), entry);
+        }
+        Ok(DirIndex { entries })
+    }
+
+    /// Look up an entry by its full path.
+    #[inline]
+    pub fn get(&self, path: &str) -> Option<&DirEntry> {
+        self.entries.get(path)
+    }
+
+    /// Number of indexed entries.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the index holds no entries.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterate over every indexed entry.
+    #[inline]
+    pub fn entries(&self) -> Values<String, DirEntry> {
+        self.entries.values()
+    }
+
+    /// Collect every entry whose path begins with the given prefix, e.g. a
+    /// directory to list its contents.
+    pub fn with_prefix(&self, prefix: &str) -> Vec<&DirEntry> {
+        self.entries
+            .values()
+            .filter(|entry| entry.file.starts_with(prefix))
+            .collect()
+    }
+}